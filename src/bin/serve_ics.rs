@@ -0,0 +1,64 @@
+//! Minimal HTTP endpoint that publishes the configured Notion database as an
+//! iCalendar feed — the reverse of the main `notion-ics` binary. Point any
+//! calendar client's "subscribe by URL" at `/calendar.ics` and it gets
+//! `SUMMARY`, `DTSTART`/`DTEND`, `LOCATION` and `UID` mapped back from the
+//! Notion properties configured in `settings`.
+
+use std::sync::Arc;
+
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use notion::NotionApi;
+use notion_ics::{find_database, query_calendar_pages, queue::RequestQueue, sync::Sync, Settings};
+use tracing::info;
+
+struct AppState {
+    notion: NotionApi,
+    settings: Settings,
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+
+    let settings = Settings::load();
+    let notion = NotionApi::new(settings.notion_token.clone()).unwrap();
+    let state = Arc::new(AppState { notion, settings });
+
+    let app = Router::new()
+        .route("/calendar.ics", get(serve_calendar))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080")
+        .await
+        .unwrap();
+    info!("Serving calendar feed on http://0.0.0.0:8080/calendar.ics");
+    axum::serve(listener, app).await.unwrap();
+}
+
+async fn serve_calendar(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    // Reads only: a single-slot queue is enough to keep this in line with
+    // Notion's rate limit without holding up the response.
+    let queue = RequestQueue::new(1);
+
+    let (database, title_property) = find_database(&state.notion, &state.settings, &queue).await;
+    let pages = query_calendar_pages(&state.notion, &database, &state.settings, &queue).await;
+
+    let sync = Sync {
+        notion: &state.notion,
+        database: &database,
+        title_property: &title_property,
+        id_property: &state.settings.id_property,
+        date_property: &state.settings.date_property,
+        location_property: state.settings.location_property.as_deref(),
+        queue: &queue,
+        orphan_policy: state.settings.orphan_policy,
+        orphan_property: state.settings.orphan_property.as_deref(),
+        property_mappings: &state.settings.property_mappings,
+        users: tokio::sync::OnceCell::new(),
+    };
+
+    (
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        sync.to_calendar(&pages).to_string(),
+    )
+}
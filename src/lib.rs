@@ -0,0 +1,161 @@
+//! Shared plumbing between the ICS-to-Notion sync binary (`main.rs`) and the
+//! Notion-to-ICS feed server (`bin/serve_ics.rs`): configuration loading and
+//! the Notion database/title-property lookup both share.
+
+pub mod queue;
+pub mod recurrence;
+pub mod source;
+pub mod sync;
+
+use config::Config;
+use notion::{
+    models::{
+        properties::PropertyConfiguration,
+        search::{DatabaseQuery, FilterCondition, NotionSearch, PropertyCondition, TextCondition},
+        Database, Object, Page,
+    },
+    NotionApi,
+};
+use serde::Deserialize;
+
+use queue::RequestQueue;
+use source::Source;
+use sync::{OrphanPolicy, PropertyMapping};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Settings {
+    /// The feed to read events from: an ICS URL, or a CalDAV collection URL
+    /// when [`Settings::source`] is [`Source::CalDav`].
+    pub ical_url: String,
+    #[serde(default)]
+    pub source: Source,
+    pub caldav_username: Option<String>,
+    pub caldav_password: Option<String>,
+    /// Bearer token for CalDAV servers using token/OAuth auth instead of
+    /// Basic; takes priority over `caldav_username`/`caldav_password` when
+    /// both are set.
+    pub caldav_token: Option<String>,
+    pub notion_token: String,
+    pub notion_calendar: String,
+    pub id_property: String,
+    pub date_property: String,
+    pub location_property: Option<String>,
+    /// What to do with pages whose event disappeared from the feed.
+    #[serde(default)]
+    pub orphan_policy: OrphanPolicy,
+    /// The checkbox property [`OrphanPolicy::Mark`] sets to `true`.
+    pub orphan_property: Option<String>,
+    /// Extra ICS-field-to-Notion-property mappings, beyond the fixed
+    /// title/id/date/location mapping.
+    #[serde(default)]
+    pub property_mappings: Vec<PropertyMapping>,
+    /// How far in the future recurring events are expanded, in months. See
+    /// [`recurrence::expand_recurring_events`].
+    #[serde(default = "default_recurrence_horizon_months")]
+    pub recurrence_horizon_months: u32,
+}
+
+fn default_recurrence_horizon_months() -> u32 {
+    6
+}
+
+impl Settings {
+    /// Load settings from `./settings.{toml,yaml,...}`, overridable through
+    /// `NOTION_ICS_`-prefixed environment variables.
+    pub fn load() -> Self {
+        let settings = Config::builder()
+            .add_source(config::File::with_name("settings"))
+            .add_source(config::Environment::with_prefix("NOTION_ICS"))
+            .build()
+            .unwrap()
+            .try_deserialize::<Settings>()
+            .unwrap();
+        settings.validate();
+        settings
+    }
+
+    /// Check invariants `try_deserialize` can't express, so a misconfigured
+    /// sync fails fast at startup instead of panicking mid-run partway
+    /// through the per-event loop.
+    fn validate(&self) {
+        if matches!(self.orphan_policy, OrphanPolicy::Mark) && self.orphan_property.is_none() {
+            panic!("orphan_property must be set when orphan_policy is \"mark\"");
+        }
+
+        let reserved = [
+            ("id_property", &self.id_property),
+            ("date_property", &self.date_property),
+        ]
+        .into_iter()
+        .chain(
+            self.location_property
+                .as_ref()
+                .map(|property| ("location_property", property)),
+        );
+        for (reserved_name, reserved_property) in reserved {
+            if let Some(mapping) = self
+                .property_mappings
+                .iter()
+                .find(|mapping| &mapping.notion_property == reserved_property)
+            {
+                panic!(
+                    "property_mappings entry for \"{}\" targets \"{reserved_property}\", \
+                     which is already used as {reserved_name}; this would overwrite it and \
+                     break create/update matching",
+                    mapping.ics_field
+                );
+            }
+        }
+    }
+}
+
+/// Find the Notion database configured by [`Settings::notion_calendar`] and
+/// the name of its title property.
+pub async fn find_database(
+    client: &NotionApi,
+    settings: &Settings,
+    queue: &RequestQueue,
+) -> (Database, String) {
+    let query = NotionSearch::Query(settings.notion_calendar.clone());
+    let databases = queue
+        .execute(|| client.search(query.clone()))
+        .await
+        .unwrap();
+    let database = match databases.results.into_iter().next().unwrap() {
+        Object::Database { database } => database,
+        _ => panic!("Not a database"),
+    };
+
+    let title_property = database
+        .properties
+        .iter()
+        .find_map(|(name, prop)| {
+            matches!(prop, PropertyConfiguration::Title { .. }).then(|| name.clone())
+        })
+        .unwrap();
+
+    (database, title_property)
+}
+
+/// Fetch the pages of `database` that carry an id, i.e. the pages this crate
+/// manages. Only the first batch of results Notion returns for the query is
+/// read; further result pages are not requested.
+pub async fn query_calendar_pages(
+    client: &NotionApi,
+    database: &Database,
+    settings: &Settings,
+    queue: &RequestQueue,
+) -> Vec<Page> {
+    let query = DatabaseQuery {
+        filter: Some(FilterCondition::Property {
+            property: settings.id_property.clone(),
+            condition: PropertyCondition::RichText(TextCondition::IsNotEmpty),
+        }),
+        ..Default::default()
+    };
+    queue
+        .execute(|| client.query_database(&database.id, query.clone()))
+        .await
+        .unwrap()
+        .results
+}
@@ -0,0 +1,252 @@
+//! Execution layer around Notion API calls.
+//!
+//! Notion enforces an average of 3 requests per second and answers anything
+//! above that with HTTP 429. [`RequestQueue`] serializes calls behind a
+//! concurrency cap and retries failed ones through a pluggable
+//! [`RequestHandler`], so a big calendar can sync without the caller having
+//! to think about throttling.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+/// Decides what to do after a failed attempt: retry after the returned delay,
+/// or give up by returning `None`. Called with the zero-based attempt number
+/// and a `Retry-After` hint when one could be recovered from the error.
+///
+/// The default policy (used by [`RequestQueue::new`]) honors `retry_after`
+/// when present and otherwise backs off exponentially, giving up after a
+/// handful of attempts. Callers can supply their own for different
+/// throttling or logging behavior.
+pub type RequestHandler = Arc<dyn Fn(u32, Option<Duration>) -> Option<Duration> + Send + Sync>;
+
+const MAX_ATTEMPTS: u32 = 5;
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Default [`RequestHandler`]: exponential backoff capped at [`MAX_ATTEMPTS`]
+/// retries, deferring to `Retry-After` whenever the failure carried one.
+pub fn default_handler() -> RequestHandler {
+    Arc::new(|attempt, retry_after| {
+        if attempt >= MAX_ATTEMPTS {
+            return None;
+        }
+        Some(retry_after.unwrap_or(BASE_BACKOFF * 2u32.pow(attempt)))
+    })
+}
+
+/// Recovers a `Retry-After` delay from a rate-limited Notion error, if any.
+///
+/// The Notion client doesn't expose the raw HTTP response, so this falls
+/// back to scanning the error's textual representation for a `retry-after`
+/// hint; callers whose error type exposes the header directly should supply
+/// their own [`RequestHandler`] instead of relying on this heuristic.
+fn rate_limit_hint<E: std::fmt::Display>(err: &E) -> Option<Duration> {
+    let message = err.to_string().to_lowercase();
+    let after = message.split("retry-after").nth(1)?;
+    let seconds: u64 = after
+        .trim_start_matches([':', ' '])
+        .split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// Whether a failure is worth retrying at all.
+#[derive(Debug, PartialEq, Eq)]
+enum Classification {
+    /// A 429: transient by definition, always worth retrying.
+    RateLimited,
+    /// A 4xx other than 429: retrying it would just fail the same way.
+    Permanent,
+    /// Anything else (connection errors, timeouts, non-HTTP failures):
+    /// assumed transient, same as this queue's historical behavior.
+    Unknown,
+}
+
+/// Classifies a Notion error by walking its [`std::error::Error::source`]
+/// chain looking for the underlying [`reqwest::Error`], rather than
+/// string-matching the error's `Display` text: the notion client wraps
+/// `reqwest::Error` for every HTTP failure, and `reqwest::Error::status`
+/// reports the real status code regardless of how the wrapper formats it.
+fn classify<E: std::error::Error + 'static>(err: &E) -> Classification {
+    let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(err);
+    while let Some(err) = cause {
+        if let Some(reqwest_err) = err.downcast_ref::<reqwest::Error>() {
+            return match reqwest_err.status() {
+                Some(status) if status.as_u16() == 429 => Classification::RateLimited,
+                Some(status) if status.is_client_error() => Classification::Permanent,
+                _ => Classification::Unknown,
+            };
+        }
+        cause = err.source();
+    }
+    Classification::Unknown
+}
+
+/// Serializes Notion API calls behind a concurrency cap, retrying failures
+/// through a [`RequestHandler`].
+pub struct RequestQueue {
+    semaphore: Semaphore,
+    handler: RequestHandler,
+}
+
+impl RequestQueue {
+    /// Build a queue with the default backoff policy, allowing up to
+    /// `max_concurrency` in-flight requests at once.
+    pub fn new(max_concurrency: usize) -> Self {
+        Self::with_handler(max_concurrency, default_handler())
+    }
+
+    /// Build a queue with a custom [`RequestHandler`], e.g. to plug in a
+    /// different backoff curve or to log every retry.
+    pub fn with_handler(max_concurrency: usize, handler: RequestHandler) -> Self {
+        Self {
+            semaphore: Semaphore::new(max_concurrency),
+            handler,
+        }
+    }
+
+    /// Run `make_call` until it succeeds or the handler gives up, capping
+    /// how many calls run concurrently across the whole queue.
+    ///
+    /// A failure classified as [`Classification::Permanent`] (a 4xx other
+    /// than 429) is returned immediately without consulting the handler:
+    /// a bad token or a malformed request fails the same way on every
+    /// attempt, so retrying it only delays reporting the real problem.
+    pub async fn execute<T, E, F, Fut>(&self, make_call: F) -> Result<T, E>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: std::error::Error + 'static,
+    {
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("request queue semaphore should never be closed");
+
+        let mut attempt = 0;
+        loop {
+            match make_call().await {
+                Ok(value) => return Ok(value),
+                Err(err) => {
+                    if matches!(classify(&err), Classification::Permanent) {
+                        return Err(err);
+                    }
+
+                    let retry_after = rate_limit_hint(&err);
+                    match (self.handler)(attempt, retry_after) {
+                        Some(delay) => {
+                            warn!("Request failed ({err}), retrying in {delay:?}");
+                            tokio::time::sleep(delay).await;
+                            attempt += 1;
+                        }
+                        None => return Err(err),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[derive(Debug)]
+    struct SyntheticError(String);
+
+    impl std::fmt::Display for SyntheticError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl std::error::Error for SyntheticError {}
+
+    #[test]
+    fn rate_limit_hint_parses_a_retry_after_seconds_value() {
+        let err = SyntheticError("rate limited, Retry-After: 42".to_string());
+        assert_eq!(rate_limit_hint(&err), Some(Duration::from_secs(42)));
+    }
+
+    #[test]
+    fn rate_limit_hint_is_case_insensitive() {
+        let err = SyntheticError("429 RATE LIMITED. RETRY-AFTER: 7 seconds".to_string());
+        assert_eq!(rate_limit_hint(&err), Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn rate_limit_hint_is_none_without_a_retry_after() {
+        let err = SyntheticError("invalid request".to_string());
+        assert_eq!(rate_limit_hint(&err), None);
+    }
+
+    /// An error type standing in for the notion crate's own error enum:
+    /// `classify` has to find the [`reqwest::Error`] through `source()`,
+    /// not by matching a concrete variant it doesn't know about.
+    #[derive(Debug)]
+    struct WrappedError(reqwest::Error);
+
+    impl std::fmt::Display for WrappedError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "wrapped: {}", self.0)
+        }
+    }
+
+    impl std::error::Error for WrappedError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    /// Spins up a one-shot raw HTTP server replying with `status_line` to
+    /// its first connection, and returns its URL. `reqwest::Error` has no
+    /// public constructor, so this is the only way to get a genuine one to
+    /// classify against.
+    fn respond_once_with(status_line: &'static str) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(
+                    format!("{status_line}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                        .as_bytes(),
+                );
+            }
+        });
+        format!("http://{addr}/")
+    }
+
+    async fn fetch_error(status_line: &'static str) -> reqwest::Error {
+        let url = respond_once_with(status_line);
+        reqwest::get(url)
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn classify_treats_429_as_rate_limited() {
+        let err = fetch_error("HTTP/1.1 429 Too Many Requests").await;
+        assert_eq!(classify(&WrappedError(err)), Classification::RateLimited);
+    }
+
+    #[tokio::test]
+    async fn classify_treats_other_4xx_as_permanent() {
+        let err = fetch_error("HTTP/1.1 404 Not Found").await;
+        assert_eq!(classify(&WrappedError(err)), Classification::Permanent);
+    }
+
+    #[test]
+    fn classify_treats_non_http_errors_as_unknown() {
+        let err = SyntheticError("not an http error".to_string());
+        assert_eq!(classify(&err), Classification::Unknown);
+    }
+}
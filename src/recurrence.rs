@@ -0,0 +1,243 @@
+//! Expansion of recurring ICS events into one synthetic event per occurrence.
+//!
+//! [`write_properties`](crate::sync::Sync) maps a single ICS `Event` to a
+//! single Notion page, which is correct for one-off events but collapses a
+//! recurring `VEVENT` (one carrying an `RRULE`) down to a single page. This
+//! module expands such a master event into the individual occurrences a
+//! calendar app would actually show, so the rest of the sync pipeline can
+//! keep treating every event as a single page. The master VEVENT itself is
+//! consumed by the expansion and never produces a page of its own.
+
+use std::collections::HashMap;
+
+use chrono::{Duration, Months, TimeZone};
+use icalendar::{Component, DatePerhapsTime, Event, EventLike};
+use rrule::{RRuleSet, Tz};
+use tracing::warn;
+
+/// Expand every recurring event in `events` into one synthetic event per
+/// occurrence, applying `RECURRENCE-ID` overrides and dropping `EXDATE`
+/// occurrences. Non-recurring events are passed through unchanged.
+///
+/// Only occurrences between now and `horizon_months` in the future are
+/// expanded: anything further out isn't synced yet (the next run will pick
+/// it up as it comes into range), and anything already finished is skipped
+/// so a long-lived recurring series doesn't replay its entire past history
+/// on every sync.
+pub fn expand_recurring_events(events: Vec<Event>, horizon_months: u32) -> Vec<Event> {
+    let mut masters = Vec::new();
+    let mut overrides: HashMap<(String, String), Event> = HashMap::new();
+    let mut expanded = Vec::new();
+
+    for event in events {
+        let uid = event.get_uid().unwrap_or_default().to_string();
+        if let Some(recurrence_id) = event.property_value("RECURRENCE-ID") {
+            overrides.insert((uid, normalize_occurrence_key(recurrence_id)), event);
+        } else if event.property_value("RRULE").is_some() {
+            masters.push(event);
+        } else {
+            expanded.push(event);
+        }
+    }
+
+    for master in masters {
+        expanded.extend(expand_master(&master, &overrides, horizon_months));
+    }
+
+    expanded
+}
+
+/// Canonicalizes a `RECURRENCE-ID`/occurrence date to the `YYYYMMDD` key
+/// occurrences are matched on, regardless of whether the value carries a
+/// time component (`20240115T090000`) or is date-only (`20240115`): both
+/// must key the same way for overrides on timed recurring events to match.
+fn normalize_occurrence_key(value: &str) -> String {
+    value.chars().take_while(|c| c.is_ascii_digit()).collect()
+}
+
+fn expand_master(
+    master: &Event,
+    overrides: &HashMap<(String, String), Event>,
+    horizon_months: u32,
+) -> Vec<Event> {
+    let uid = master.get_uid().unwrap_or_default().to_string();
+
+    let Some(rule_set) = parse_rule_set(master) else {
+        warn!("Failed to parse RRULE for {uid}, skipping recurrence expansion");
+        return vec![];
+    };
+
+    let Some(start) = master.get_start() else {
+        warn!("Recurring event {uid} has no DTSTART, skipping");
+        return vec![];
+    };
+    let Some(end) = master.get_end() else {
+        warn!("Recurring event {uid} has no DTEND, skipping");
+        return vec![];
+    };
+    let duration = duration_between(start, end);
+
+    let now = Tz::UTC.from_utc_datetime(&chrono::Utc::now().naive_utc());
+    let horizon = Tz::UTC.from_utc_datetime(
+        &(chrono::Utc::now() + Months::new(horizon_months)).naive_utc(),
+    );
+
+    rule_set
+        .into_iter()
+        .skip_while(|occurrence| *occurrence + duration < now)
+        .take_while(|occurrence| *occurrence <= horizon)
+        .filter_map(|occurrence| {
+            let recurrence_date = occurrence.format("%Y%m%d").to_string();
+            let key = (uid.clone(), recurrence_date.clone());
+            let synthetic_uid = format!("{uid}@{recurrence_date}");
+
+            if let Some(overridden) = overrides.get(&key) {
+                let mut occurrence = overridden.clone();
+                occurrence.uid(&synthetic_uid);
+                return Some(occurrence);
+            }
+
+            let occurrence_start = shift_to(start, occurrence);
+            let occurrence_end = add_duration(occurrence_start, duration);
+
+            let mut event = master.clone();
+            event.uid(&synthetic_uid);
+            event.starts(occurrence_start);
+            event.ends(occurrence_end);
+            Some(event)
+        })
+        .collect()
+}
+
+/// Builds an [`RRuleSet`] from a master event's `DTSTART`, `RRULE` and
+/// `EXDATE` properties.
+fn parse_rule_set(master: &Event) -> Option<RRuleSet> {
+    let dtstart = property_line(master, "DTSTART")?;
+    let rrule = master.property_value("RRULE")?;
+    let mut ics = format!("{dtstart}\nRRULE:{rrule}");
+    if let Some(exdate) = property_line(master, "EXDATE") {
+        ics.push_str(&format!("\n{exdate}"));
+    }
+    ics.parse().ok()
+}
+
+/// Renders `name`'s property back into an ICS content line, keeping its
+/// `TZID` parameter: `property_value` alone drops parameters, which silently
+/// computes occurrences in the wrong zone for non-UTC recurring events.
+fn property_line(master: &Event, name: &str) -> Option<String> {
+    let property = master.properties().get(name)?;
+    let tzid = property
+        .params()
+        .get("TZID")
+        .map(|tzid| format!(";TZID={}", tzid.value()));
+    Some(format!("{name}{}:{}", tzid.unwrap_or_default(), property.value()))
+}
+
+fn duration_between(start: DatePerhapsTime, end: DatePerhapsTime) -> Duration {
+    match (start, end) {
+        (DatePerhapsTime::Date(start), DatePerhapsTime::Date(end)) => end.signed_duration_since(start),
+        (DatePerhapsTime::DateTime(start), DatePerhapsTime::DateTime(end)) => end
+            .try_into_utc()
+            .unwrap()
+            .signed_duration_since(start.try_into_utc().unwrap()),
+        _ => Duration::zero(),
+    }
+}
+
+fn shift_to(template: DatePerhapsTime, occurrence: chrono::DateTime<Tz>) -> DatePerhapsTime {
+    match template {
+        DatePerhapsTime::Date(_) => DatePerhapsTime::Date(occurrence.date_naive()),
+        DatePerhapsTime::DateTime(_) => DatePerhapsTime::DateTime(occurrence.with_timezone(&chrono::Utc).into()),
+    }
+}
+
+fn add_duration(start: DatePerhapsTime, duration: Duration) -> DatePerhapsTime {
+    match start {
+        DatePerhapsTime::Date(date) => DatePerhapsTime::Date(date + duration),
+        DatePerhapsTime::DateTime(datetime) => {
+            DatePerhapsTime::DateTime((datetime.try_into_utc().unwrap() + duration).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use icalendar::Calendar;
+
+    fn parse_event(ics_body: &str) -> Event {
+        let calendar: Calendar = format!("BEGIN:VCALENDAR\r\n{ics_body}END:VCALENDAR\r\n")
+            .parse()
+            .expect("test fixture should be valid ICS");
+        calendar
+            .into_iter()
+            .find_map(|component| component.as_event().cloned())
+            .expect("test fixture should contain a VEVENT")
+    }
+
+    /// Starts tomorrow so that no occurrence has already finished and been
+    /// skipped, whatever time of day the test runs.
+    fn daily_series(uid: &str, extra: &str) -> Event {
+        let dtstart = (Utc::now() + Duration::days(1))
+            .format("%Y%m%dT090000Z")
+            .to_string();
+        parse_event(&format!(
+            "BEGIN:VEVENT\r\nUID:{uid}\r\nDTSTART:{dtstart}\r\nDTEND:{dtstart}\r\nSUMMARY:Standup\r\nRRULE:FREQ=DAILY;COUNT=3\r\n{extra}END:VEVENT\r\n"
+        ))
+    }
+
+    #[test]
+    fn expands_each_occurrence_of_a_bounded_rrule() {
+        let master = daily_series("series-1", "");
+        let occurrences = expand_recurring_events(vec![master], 6);
+        assert_eq!(occurrences.len(), 3);
+        assert!(occurrences
+            .iter()
+            .all(|event| event.get_uid().unwrap().starts_with("series-1@")));
+    }
+
+    #[test]
+    fn exdate_drops_the_excluded_occurrence() {
+        let exdate = Utc::now()
+            .checked_add_signed(Duration::days(1))
+            .unwrap()
+            .format("EXDATE:%Y%m%dT090000Z\r\n")
+            .to_string();
+        let master = daily_series("series-2", &exdate);
+        let occurrences = expand_recurring_events(vec![master], 6);
+        assert_eq!(occurrences.len(), 2);
+    }
+
+    #[test]
+    fn recurrence_id_override_matches_a_timed_occurrence() {
+        let master = daily_series("series-3", "");
+        let override_date = (Utc::now() + Duration::days(1))
+            .format("%Y%m%dT090000Z")
+            .to_string();
+        let overridden = parse_event(&format!(
+            "BEGIN:VEVENT\r\nUID:series-3\r\nRECURRENCE-ID:{override_date}\r\nDTSTART:{override_date}\r\nDTEND:{override_date}\r\nSUMMARY:Standup (moved)\r\nEND:VEVENT\r\n"
+        ));
+
+        let occurrences = expand_recurring_events(vec![master, overridden], 6);
+        let summaries: Vec<_> = occurrences
+            .iter()
+            .map(|event| event.get_summary().unwrap_or_default())
+            .collect();
+        assert!(summaries.contains(&"Standup (moved)"));
+    }
+
+    #[test]
+    fn past_occurrences_fall_outside_the_horizon() {
+        let dtstart = Utc::now()
+            .checked_sub_signed(Duration::days(400))
+            .unwrap()
+            .format("%Y%m%dT090000Z")
+            .to_string();
+        let master = parse_event(&format!(
+            "BEGIN:VEVENT\r\nUID:series-4\r\nDTSTART:{dtstart}\r\nDTEND:{dtstart}\r\nSUMMARY:Old standup\r\nRRULE:FREQ=DAILY;COUNT=500\r\nEND:VEVENT\r\n"
+        ));
+        let occurrences = expand_recurring_events(vec![master], 6);
+        assert!(occurrences.len() < 500);
+    }
+}
@@ -0,0 +1,168 @@
+//! Where events come from: a plain ICS URL fetched with a single GET, or a
+//! CalDAV collection behind HTTP Basic auth exposing multiple `.ics`
+//! objects (Nextcloud, Fastmail, iCloud and friends).
+
+use icalendar::*;
+use reqwest::{Client, Method};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::Settings;
+
+/// Which kind of feed [`Settings::ical_url`] points at.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    #[default]
+    Ics,
+    CalDav,
+}
+
+/// Fetch every `VEVENT` from the configured source as owned [`Event`]s.
+pub async fn fetch_events(settings: &Settings) -> Vec<Event> {
+    match settings.source {
+        Source::Ics => fetch_ics(&settings.ical_url).await,
+        Source::CalDav => fetch_caldav(settings).await,
+    }
+}
+
+async fn fetch_ics(url: &str) -> Vec<Event> {
+    let calendar = reqwest::get(url)
+        .await
+        .expect("Failed to fetch calendar")
+        .text()
+        .await
+        .expect("Failed to read calendar")
+        .parse::<Calendar>()
+        .expect("Failed to parse calendar");
+
+    events_of(calendar)
+}
+
+const CALDAV_REPORT_BODY: &str = r#"<?xml version="1.0" encoding="utf-8" ?>
+<C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop>
+    <C:calendar-data/>
+  </D:prop>
+  <C:filter>
+    <C:comp-filter name="VCALENDAR">
+      <C:comp-filter name="VEVENT"/>
+    </C:comp-filter>
+  </C:filter>
+</C:calendar-query>"#;
+
+/// Issue a CalDAV `REPORT calendar-query` against the configured collection
+/// URL and merge every returned `calendar-data` object's events together.
+async fn fetch_caldav(settings: &Settings) -> Vec<Event> {
+    let client = Client::new();
+    let mut request = client
+        .request(
+            Method::from_bytes(b"REPORT").expect("REPORT is a valid HTTP method token"),
+            &settings.ical_url,
+        )
+        .header("Depth", "1")
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(CALDAV_REPORT_BODY);
+
+    if let Some(token) = &settings.caldav_token {
+        request = request.bearer_auth(token);
+    } else if let Some(username) = &settings.caldav_username {
+        request = request.basic_auth(username, settings.caldav_password.as_ref());
+    }
+
+    let response = request
+        .send()
+        .await
+        .expect("Failed to query CalDAV collection")
+        .text()
+        .await
+        .expect("Failed to read CalDAV response");
+
+    extract_calendar_data(&response)
+        .into_iter()
+        .filter_map(|ics| match ics.parse::<Calendar>() {
+            Ok(calendar) => Some(events_of(calendar)),
+            Err(err) => {
+                warn!("Failed to parse a CalDAV calendar-data object: {err}");
+                None
+            }
+        })
+        .flatten()
+        .collect()
+}
+
+fn events_of(calendar: Calendar) -> Vec<Event> {
+    calendar
+        .into_iter()
+        .filter_map(|component| component.as_event().cloned())
+        .collect()
+}
+
+/// Pull the text out of every `<calendar-data>` element in a CalDAV
+/// multistatus response, tolerant of whatever namespace prefix the server
+/// used, and unwrap it regardless of whether the server XML-escaped the
+/// iCalendar text inline or wrapped it in a `CDATA` section (Nextcloud,
+/// Fastmail and iCloud all do the latter).
+///
+/// This is a deliberately narrow scan, not a general XML parser: it only
+/// needs to find this one element, reliably, in a response this crate
+/// controls the shape of ([`CALDAV_REPORT_BODY`] asks for exactly one
+/// `calendar-data` property). Two things it still has to get right to avoid
+/// desyncing the rest of the loop: a self-closing `<C:calendar-data/>` (an
+/// empty calendar object some servers return) must not be treated as an
+/// opening tag with content, and the closing tag it looks for must be this
+/// element's own `</{prefix}calendar-data>`, not just the next `"</"` —
+/// otherwise a literal `</` inside the calendar data itself (e.g. in a
+/// `DESCRIPTION` or URL) would truncate the block early and misalign every
+/// subsequent match.
+fn extract_calendar_data(xml: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut rest = xml;
+    while let Some(rel_start) = rest.find("calendar-data") {
+        let Some(lt) = rest[..rel_start].rfind('<') else {
+            break;
+        };
+        let Some(tag_end) = rest[rel_start..].find('>') else {
+            break;
+        };
+        let tag_end = rel_start + tag_end;
+        let tag = &rest[lt + 1..tag_end];
+        let prefix = &rest[lt + 1..rel_start];
+
+        if tag.trim_end().ends_with('/') {
+            // Self-closing <C:calendar-data/>: no content, nothing to add.
+            rest = &rest[tag_end + 1..];
+            continue;
+        }
+
+        let after_open = &rest[tag_end + 1..];
+        let close_tag = format!("</{prefix}calendar-data>");
+        let Some(close_start) = after_open.find(&close_tag) else {
+            break;
+        };
+        blocks.push(unwrap_calendar_data(&after_open[..close_start]));
+        rest = &after_open[close_start + close_tag.len()..];
+    }
+    blocks
+}
+
+/// Strip a `<![CDATA[...]]>` wrapper if present (its contents are raw, not
+/// entity-escaped), otherwise unescape the inline XML entities.
+fn unwrap_calendar_data(text: &str) -> String {
+    let trimmed = text.trim();
+    match trimmed
+        .strip_prefix("<![CDATA[")
+        .and_then(|rest| rest.strip_suffix("]]>"))
+    {
+        Some(cdata) => cdata.to_string(),
+        None => unescape_xml(trimmed),
+    }
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
@@ -5,12 +5,53 @@ use notion::{
     ids::PropertyId,
     models::{
         page::UpdatePageQuery,
-        properties::{DateOrDateTime, DateValue, PropertyValue, WritePropertyValue},
+        properties::{DateOrDateTime, DateValue, PropertyValue, SelectedValue, WritePropertyValue},
         text::{RichText, RichTextCommon, Text},
-        Database, Page, PageCreateRequest, Parent, Properties, WriteProperties,
+        Database, Page, PageCreateRequest, Parent, Properties, User, WriteProperties,
     },
     *,
 };
+use serde::Deserialize;
+use tracing::{error, info, warn};
+
+use crate::queue::RequestQueue;
+
+/// What to do with a page whose event has disappeared from the feed.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrphanPolicy {
+    /// Leave the page untouched (the historical behavior).
+    #[default]
+    Ignore,
+    /// Archive the page.
+    Archive,
+    /// Set [`Sync::orphan_property`] to `true` on the page.
+    Mark,
+}
+
+/// The Notion property type an ICS field is mapped to by a [`PropertyMapping`].
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MappingTarget {
+    Text,
+    Select,
+    MultiSelect,
+    Email,
+    /// Resolved against the workspace's members by email.
+    People,
+    Url,
+}
+
+/// A rule mapping an arbitrary ICS field (`DESCRIPTION`, `STATUS`,
+/// `CATEGORIES`, `ORGANIZER`, a custom `X-` property, ...) to a typed Notion
+/// property, beyond the fixed title/id/date/location mapping
+/// [`Sync::write_properties`] always applies.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PropertyMapping {
+    pub ics_field: String,
+    pub notion_property: String,
+    pub target: MappingTarget,
+}
 
 pub struct Sync<'a> {
     pub notion: &'a NotionApi,
@@ -19,11 +60,24 @@ pub struct Sync<'a> {
     pub id_property: &'a str,
     pub date_property: &'a str,
     pub location_property: Option<&'a str>,
+    pub queue: &'a RequestQueue,
+    pub orphan_policy: OrphanPolicy,
+    /// The checkbox property [`OrphanPolicy::Mark`] sets to `true`. Required
+    /// when `orphan_policy` is [`OrphanPolicy::Mark`].
+    pub orphan_property: Option<&'a str>,
+    pub property_mappings: &'a [PropertyMapping],
+    /// Workspace members, fetched once and reused for every
+    /// [`MappingTarget::People`] mapping in this sync: a recurring series can
+    /// expand into hundreds of synthetic events, and `list_users` is too
+    /// expensive (and too easy to rate-limit) to call once per event.
+    pub users: tokio::sync::OnceCell<Vec<User>>,
 }
 
 impl Sync<'_> {
-    /// Build the list of properties to write given an ical event
-    fn write_properties(&self, event: &Event) -> WriteProperties {
+    /// Build the list of properties to write given an ical event: the fixed
+    /// title/id/date/location mapping, plus whatever [`Sync::property_mappings`]
+    /// adds on top.
+    async fn write_properties(&self, event: &Event) -> WriteProperties {
         let mut properties: HashMap<String, WritePropertyValue> = HashMap::new();
 
         let new_title = event.get_summary().unwrap_or_default();
@@ -50,12 +104,75 @@ impl Sync<'_> {
         if let (Some(location), Some(property)) = (event.get_location(), self.location_property) {
             properties.insert(property.to_string(), text_write_property(location));
         }
+
+        for mapping in self.property_mappings {
+            if let Some(value) = self.mapped_write_value(event, mapping).await {
+                properties.insert(mapping.notion_property.clone(), value);
+            }
+        }
+
         WriteProperties { properties }
     }
 
+    /// Read `mapping.ics_field` off `event` and convert it to the
+    /// [`MappingTarget`] Notion property type, if the field is present.
+    async fn mapped_write_value(
+        &self,
+        event: &Event,
+        mapping: &PropertyMapping,
+    ) -> Option<WritePropertyValue> {
+        let raw = event.property_value(&mapping.ics_field)?;
+        match mapping.target {
+            MappingTarget::Text => Some(text_write_property(raw)),
+            MappingTarget::Select => Some(select_write_property(raw)),
+            MappingTarget::MultiSelect => Some(multi_select_write_property(raw)),
+            MappingTarget::Email => Some(WritePropertyValue::Email {
+                email: Some(strip_mailto(raw)),
+            }),
+            MappingTarget::Url => Some(WritePropertyValue::Url {
+                url: Some(raw.to_string()),
+            }),
+            MappingTarget::People => {
+                let people = self.resolve_people(&strip_mailto(raw)).await;
+                (!people.is_empty()).then_some(WritePropertyValue::People { people })
+            }
+        }
+    }
+
+    /// The workspace's members, fetched once per sync (through the
+    /// [`RequestQueue`], so it backs off like any other Notion call) and
+    /// cached in [`Sync::users`] for every subsequent lookup.
+    async fn workspace_users(&self) -> &[User] {
+        self.users
+            .get_or_init(|| async {
+                match self.queue.execute(|| self.notion.list_users()).await {
+                    Ok(users) => users.results,
+                    Err(err) => {
+                        warn!("Failed to list workspace users: {err}");
+                        vec![]
+                    }
+                }
+            })
+            .await
+    }
+
+    /// Match an email address against the workspace's members.
+    async fn resolve_people(&self, email: &str) -> Vec<User> {
+        self.workspace_users()
+            .await
+            .iter()
+            .filter(|user| {
+                user.person
+                    .as_ref()
+                    .is_some_and(|person| person.email.eq_ignore_ascii_case(email))
+            })
+            .cloned()
+            .collect()
+    }
+
     /// Build a page creation request given an ical event
-    pub fn create_request(&self, event: &Event) -> PageCreateRequest {
-        let properties = page_properties(self.write_properties(event));
+    pub async fn create_request(&self, event: &Event) -> PageCreateRequest {
+        let properties = page_properties(self.write_properties(event).await);
         PageCreateRequest {
             parent: Parent::Database {
                 database_id: self.database.id.clone(),
@@ -64,8 +181,12 @@ impl Sync<'_> {
         }
     }
 
-    pub fn update_request(&self, event: &Event, notion_event: &Page) -> Option<UpdatePageQuery> {
-        let properties = self.write_properties(event);
+    pub async fn update_request(
+        &self,
+        event: &Event,
+        notion_event: &Page,
+    ) -> Option<UpdatePageQuery> {
+        let properties = self.write_properties(event).await;
 
         // Filter out properties that are already up to date
         let properties: HashMap<String, WritePropertyValue> = properties
@@ -90,6 +211,169 @@ impl Sync<'_> {
             ..Default::default()
         })
     }
+
+    /// Create a page based on an event, retrying through the [`RequestQueue`]
+    /// if Notion rate-limits the request.
+    pub async fn create(&self, event: &Event) {
+        info!("Creating {}", event.get_summary().unwrap_or_default());
+        let request = self.create_request(event).await;
+        let result = self
+            .queue
+            .execute(|| self.notion.create_page(request.clone()))
+            .await;
+        if let Err(err) = result {
+            error!("Failed to create page for {}: {err}", request_uid(event));
+        }
+    }
+
+    /// Update a page based on an event, retrying through the [`RequestQueue`]
+    /// if Notion rate-limits the request.
+    pub async fn update(&self, event: &Event, notion_event: &Page) {
+        let Some(query) = self.update_request(event, notion_event).await else {
+            info!("{} is up to date", event.get_summary().unwrap_or_default());
+            return;
+        };
+
+        info!("Updating {}", event.get_summary().unwrap_or_default());
+        let result = self
+            .queue
+            .execute(|| self.notion.update_page(&notion_event.id, query.clone()))
+            .await;
+        if let Err(err) = result {
+            error!("Failed to update page for {}: {err}", request_uid(event));
+        }
+    }
+
+    /// Apply [`Sync::orphan_policy`] to a page whose event is no longer in
+    /// the feed.
+    pub async fn orphan(&self, notion_event: &Page) {
+        let query = match self.orphan_policy {
+            OrphanPolicy::Ignore => {
+                info!("{} is in Notion but not in the feed", notion_event.id);
+                return;
+            }
+            OrphanPolicy::Archive => UpdatePageQuery {
+                archived: Some(true),
+                ..Default::default()
+            },
+            OrphanPolicy::Mark => {
+                let property = self
+                    .orphan_property
+                    .expect("Settings::validate guarantees this is set when orphan_policy is Mark");
+                let mut properties = HashMap::new();
+                properties.insert(
+                    property.to_string(),
+                    WritePropertyValue::Checkbox { checkbox: true },
+                );
+                UpdatePageQuery {
+                    properties: Some(WriteProperties { properties }),
+                    ..Default::default()
+                }
+            }
+        };
+
+        info!("Marking {} as orphaned ({:?})", notion_event.id, self.orphan_policy);
+        let result = self
+            .queue
+            .execute(|| self.notion.update_page(&notion_event.id, query.clone()))
+            .await;
+        if let Err(err) = result {
+            error!("Failed to orphan page {}: {err}", notion_event.id);
+        }
+    }
+
+    /// Build an iCalendar feed from a set of Notion pages — the mirror image
+    /// of [`write_properties`](Self::write_properties): the title property
+    /// becomes `SUMMARY`, the date property becomes `DTSTART`/`DTEND`
+    /// (re-adding the day [`date_range`] strips, since Notion's range is
+    /// inclusive and ICS's is exclusive), the location property becomes
+    /// `LOCATION`, and the id property becomes `UID`, falling back to the
+    /// page id for pages without one.
+    pub fn to_calendar(&self, pages: &[Page]) -> Calendar {
+        let mut calendar = Calendar::new();
+        for page in pages {
+            calendar.push(self.event_from_page(page));
+        }
+        calendar.done()
+    }
+
+    fn event_from_page(&self, page: &Page) -> Event {
+        let mut event = Event::new();
+
+        if let Some(title) = text_property(page, self.title_property) {
+            event.summary(&title);
+        }
+
+        if let Some(PropertyValue::Date {
+            date: Some(date), ..
+        }) = page.properties.properties.get(self.date_property)
+        {
+            let (start, end) = date_range_back(date);
+            event.starts(start);
+            event.ends(end);
+        }
+
+        if let Some(location_property) = self.location_property {
+            if let Some(location) = text_property(page, location_property) {
+                event.location(&location);
+            }
+        }
+
+        let uid = text_property(page, self.id_property)
+            .filter(|uid| !uid.is_empty())
+            .unwrap_or_else(|| page.id.to_string());
+        event.uid(&uid);
+
+        event.done()
+    }
+}
+
+fn request_uid(event: &Event) -> &str {
+    event.get_uid().unwrap_or_default()
+}
+
+/// Read a title or rich-text property as plain text.
+fn text_property(page: &Page, property: &str) -> Option<String> {
+    match page.properties.properties.get(property)? {
+        PropertyValue::Title { title, .. } => Some(plain_text(title)),
+        PropertyValue::Text { rich_text, .. } => Some(plain_text(rich_text)),
+        _ => None,
+    }
+}
+
+fn plain_text(rich_text: &[RichText]) -> String {
+    rich_text.iter().map(|t| t.plain_text()).collect()
+}
+
+/// Reverse of [`date_range`]: turn a Notion date range back into the
+/// start/end pair an ICS event expects, re-adding the day `date_range`
+/// stripped off an all-day event's end date.
+fn date_range_back(date: &DateValue) -> (DatePerhapsTime, DatePerhapsTime) {
+    match &date.start {
+        DateOrDateTime::Date(start) => {
+            let start = *start;
+            let end = match &date.end {
+                Some(DateOrDateTime::Date(end)) => end
+                    .succ_opt()
+                    .expect("Is this the heat death of the universe or what"),
+                _ => start
+                    .succ_opt()
+                    .expect("Is this the heat death of the universe or what"),
+            };
+            (DatePerhapsTime::Date(start), DatePerhapsTime::Date(end))
+        }
+        DateOrDateTime::DateTime(start) => {
+            let start = *start;
+            let end = match &date.end {
+                Some(DateOrDateTime::DateTime(end)) => *end,
+                _ => start,
+            };
+            (
+                DatePerhapsTime::DateTime(CalendarDateTime::Utc(start)),
+                DatePerhapsTime::DateTime(CalendarDateTime::Utc(end)),
+            )
+        }
+    }
 }
 
 /// Convert a WritePropertyValue to a PropertyValue with empty ID (not necessary in most calls)
@@ -109,7 +393,11 @@ fn page_property(write_property: WritePropertyValue) -> PropertyValue {
         WritePropertyValue::PhoneNumber { phone_number } => {
             PropertyValue::PhoneNumber { id, phone_number }
         }
-        _ => todo!(),
+        WritePropertyValue::Select { select } => PropertyValue::Select { id, select },
+        WritePropertyValue::MultiSelect { multi_select } => {
+            PropertyValue::MultiSelect { id, multi_select }
+        }
+        _ => unreachable!("write_properties never produces this property type"),
     }
 }
 
@@ -146,10 +434,61 @@ fn property_comp(property: &PropertyValue, write_property: &WritePropertyValue)
         (PropertyValue::Date { date, .. }, WritePropertyValue::Date { date: new_date }) => {
             date == new_date
         }
-        _ => todo!(),
+        (
+            PropertyValue::Relation { relation, .. },
+            WritePropertyValue::Relation {
+                relation: new_relation,
+            },
+        ) => relation == new_relation,
+        (
+            PropertyValue::People { people, .. },
+            WritePropertyValue::People { people: new_people },
+        ) => people == new_people,
+        (PropertyValue::Files { files, .. }, WritePropertyValue::Files { files: new_files }) => {
+            files == new_files
+        }
+        (
+            PropertyValue::Checkbox { checkbox, .. },
+            WritePropertyValue::Checkbox {
+                checkbox: new_checkbox,
+            },
+        ) => checkbox == new_checkbox,
+        (PropertyValue::Url { url, .. }, WritePropertyValue::Url { url: new_url }) => {
+            url == new_url
+        }
+        (PropertyValue::Email { email, .. }, WritePropertyValue::Email { email: new_email }) => {
+            email == new_email
+        }
+        (
+            PropertyValue::PhoneNumber { phone_number, .. },
+            WritePropertyValue::PhoneNumber {
+                phone_number: new_phone_number,
+            },
+        ) => phone_number == new_phone_number,
+        (
+            PropertyValue::Select { select, .. },
+            WritePropertyValue::Select { select: new_select },
+        ) => select_name(select) == select_name(new_select),
+        (
+            PropertyValue::MultiSelect { multi_select, .. },
+            WritePropertyValue::MultiSelect {
+                multi_select: new_multi_select,
+            },
+        ) => {
+            let mut current: Vec<_> = multi_select.iter().map(|s| &s.name).collect();
+            let mut new: Vec<_> = new_multi_select.iter().map(|s| &s.name).collect();
+            current.sort();
+            new.sort();
+            current == new
+        }
+        _ => false,
     }
 }
 
+fn select_name(select: &Option<SelectedValue>) -> Option<&str> {
+    select.as_ref()?.name.as_deref()
+}
+
 fn text_write_property(text: &str) -> WritePropertyValue {
     WritePropertyValue::Text {
         rich_text: rich_text(text),
@@ -168,6 +507,39 @@ fn title_write_property(text: &str) -> WritePropertyValue {
     }
 }
 
+fn select_write_property(value: &str) -> WritePropertyValue {
+    WritePropertyValue::Select {
+        select: Some(SelectedValue {
+            id: None,
+            name: Some(value.trim().to_string()),
+            color: None,
+        }),
+    }
+}
+
+/// Split a comma-separated ICS `CATEGORIES` value into Notion multi-select
+/// options.
+fn multi_select_write_property(value: &str) -> WritePropertyValue {
+    WritePropertyValue::MultiSelect {
+        multi_select: value
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(|value| SelectedValue {
+                id: None,
+                name: Some(value.to_string()),
+                color: None,
+            })
+            .collect(),
+    }
+}
+
+/// Strip the `mailto:` scheme ICS `ORGANIZER`/`ATTENDEE` values are prefixed
+/// with, leaving a plain email address.
+fn strip_mailto(value: &str) -> String {
+    value.strip_prefix("mailto:").unwrap_or(value).to_string()
+}
+
 fn rich_text(text: &str) -> Vec<RichText> {
     vec![RichText::Text {
         rich_text: RichTextCommon {